@@ -1,14 +1,34 @@
-use std::{hint::unreachable_unchecked, io};
+use std::{collections::VecDeque, hint::unreachable_unchecked, io, mem::MaybeUninit};
 
-use bytes::{Buf, BufMut, BytesMut};
-use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt};
+use bytes::{Buf, BytesMut};
+use monoio::io::{AsyncReadRent, AsyncWriteRent};
 
-const BUFFER_SIZE: usize = 16 * 1024;
+// default buffer capacity used when a SafeRead/SafeWrite is created via `Default`.
+const DEFAULT_BUFFER_SIZE: usize = 16 * 1024;
+
+// upper bound for the adaptive read reserve, so one bulk transfer can't grow it unbounded.
+const MAX_RESERVE_SIZE: usize = 1024 * 1024;
+
+// consecutive mostly-empty reads required before `adapt_reserve_size` actually shrinks (and
+// so reallocates) the reservation. `ensure_capacity` reallocates on every change, which would
+// otherwise throw away `ReadCursor`'s `initialized` high-water mark on the very next read of
+// a bursty connection that just happened to come back small once.
+const SHRINK_STREAK_THRESHOLD: u32 = 4;
 
 pub(crate) struct SafeRead {
     // the option is only meant for temporary take, it always should be some
-    buffer: Option<BytesMut>,
+    cursor: Option<ReadCursor>,
     status: ReadStatus,
+    // floor for `reserve_size`, set once at construction time.
+    min_reserve_size: usize,
+    // how much we ask the cursor to make room for before the next read; grows when reads
+    // keep filling the buffer and shrinks back down when they don't, so large transfers
+    // stop being split across many small reads without over-allocating for small ones.
+    reserve_size: usize,
+    // consecutive reads in a row that came back under a quarter full; only once this hits
+    // `SHRINK_STREAK_THRESHOLD` do we actually shrink, so one quiet read in a bursty stream
+    // doesn't reallocate the buffer out from under the next large one.
+    underfill_streak: u32,
 }
 
 enum ReadStatus {
@@ -19,49 +39,164 @@ enum ReadStatus {
 
 impl Default for SafeRead {
     fn default() -> Self {
+        Self::with_capacity(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+// a ReadBuf-like cursor over a backing allocation that may be partially uninitialized.
+// pos <= filled <= initialized <= data.len(): pos..filled is read but not yet consumed,
+// filled..initialized was written by a prior read and is safe to read again without
+// re-initializing. reclaim() resets pos/filled to 0 once consumed, without losing that
+// initialized prefix or reallocating.
+struct ReadCursor {
+    data: Box<[MaybeUninit<u8>]>,
+    pos: usize,
+    filled: usize,
+    initialized: usize,
+}
+
+impl ReadCursor {
+    fn with_capacity(capacity: usize) -> Self {
         Self {
-            buffer: Some(BytesMut::default()),
-            status: ReadStatus::Ok,
+            data: vec![MaybeUninit::uninit(); capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+            initialized: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.filled
+    }
+
+    fn remaining(&self) -> usize {
+        self.filled - self.pos
+    }
+
+    // resizes the backing allocation to exactly `capacity`, growing or shrinking, so the
+    // bytes actually offered to the next read always match the adaptive reserve target
+    // instead of drifting from it. a no-op when it already matches. only ever called on an
+    // empty cursor, so there is nothing to preserve across the resize.
+    fn ensure_capacity(self, capacity: usize) -> Self {
+        debug_assert!(self.is_empty());
+        if capacity == self.data.len() {
+            return self;
+        }
+        Self::with_capacity(capacity)
+    }
+
+    // resets the cursor to the front of the backing allocation once all filled data has
+    // been consumed, reclaiming the space without a reallocation. `initialized` is left
+    // untouched: that prefix of `data` was genuinely written to by the kernel and stays
+    // safe to read from without the next write re-initializing it.
+    fn reclaim(&mut self) {
+        if self.is_empty() {
+            self.pos = 0;
+            self.filled = 0;
+        }
+    }
+}
+
+unsafe impl monoio::buf::IoBuf for ReadCursor {
+    fn read_ptr(&self) -> *const u8 {
+        self.data.as_ptr() as *const u8
+    }
+
+    fn bytes_init(&self) -> usize {
+        self.filled
+    }
+}
+
+unsafe impl monoio::buf::IoBufMut for ReadCursor {
+    fn write_ptr(&mut self) -> *mut u8 {
+        unsafe { (self.data.as_mut_ptr() as *mut u8).add(self.filled) }
+    }
+
+    fn bytes_total(&self) -> usize {
+        self.data.len() - self.filled
+    }
+
+    unsafe fn set_init(&mut self, pos: usize) {
+        let newly_initialized = self.filled + pos;
+        if newly_initialized > self.initialized {
+            self.initialized = newly_initialized;
         }
     }
 }
 
 impl SafeRead {
+    // creates a SafeRead whose initial (and minimum) read reservation is `capacity`.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            cursor: Some(ReadCursor::with_capacity(capacity)),
+            status: ReadStatus::Ok,
+            min_reserve_size: capacity,
+            reserve_size: capacity,
+            underfill_streak: 0,
+        }
+    }
+
     pub(crate) async fn do_io<IO: AsyncReadRent>(&mut self, mut io: IO) -> io::Result<usize> {
-        // if there are some data inside the buffer, just return.
-        let buffer = self.buffer.as_ref().expect("buffer ref expected");
-        if !buffer.is_empty() {
-            return Ok(buffer.len());
+        // if there are some data inside the cursor, just return.
+        let cursor = self.cursor.as_ref().expect("cursor ref expected");
+        if !cursor.is_empty() {
+            return Ok(cursor.remaining());
         }
 
         // read from raw io
-        let mut buffer = self.buffer.take().expect("buffer ownership expected");
-        buffer.reserve(BUFFER_SIZE);
-        let (result, buf) = io.read(buffer).await;
-        self.buffer = Some(buf);
-        match result {
+        let reserve_size = self.reserve_size;
+        let cursor = self
+            .cursor
+            .take()
+            .expect("cursor ownership expected")
+            .ensure_capacity(reserve_size);
+        let (result, mut cursor) = io.read(cursor).await;
+        match &result {
             Ok(0) => {
                 self.status = ReadStatus::Eof;
-                return result;
             }
-            Ok(_) => {
+            Ok(n) => {
+                cursor.filled += n;
                 self.status = ReadStatus::Ok;
-                return result;
+                self.adapt_reserve_size(*n, reserve_size);
             }
             Err(e) => {
-                let rerr = e.kind().into();
-                self.status = ReadStatus::Err(e);
-                return Err(rerr);
+                self.status = ReadStatus::Err(e.kind().into());
             }
         }
+        self.cursor = Some(cursor);
+        result.map_err(|e| e.kind().into())
+    }
+
+    // grows the reservation (up to `MAX_RESERVE_SIZE`) as soon as a read fills the buffer it
+    // was given, so a single large transfer doesn't keep getting split across reads. shrinks
+    // it back towards `min_reserve_size` only after `SHRINK_STREAK_THRESHOLD` reads in a row
+    // come back mostly empty: growth reallocates once for a transfer that's actually using
+    // the space, but an ungated shrink would reallocate on every lull in bursty traffic,
+    // throwing away the `initialized` high-water mark the cursor just built up.
+    fn adapt_reserve_size(&mut self, filled: usize, reserved: usize) {
+        if filled >= reserved {
+            self.reserve_size = (reserved * 2).min(MAX_RESERVE_SIZE);
+            self.underfill_streak = 0;
+            return;
+        }
+        if filled >= reserved / 4 {
+            self.underfill_streak = 0;
+            return;
+        }
+        self.underfill_streak += 1;
+        if self.underfill_streak >= SHRINK_STREAK_THRESHOLD {
+            self.reserve_size = (reserved / 2).max(self.min_reserve_size);
+            self.underfill_streak = 0;
+        }
     }
 }
 
 impl io::Read for SafeRead {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // if buffer is empty, return WoundBlock.
-        let buffer = self.buffer.as_mut().expect("buffer mut expected");
-        if buffer.is_empty() {
+        // if the cursor is empty, return WoundBlock.
+        let cursor = self.cursor.as_mut().expect("cursor mut expected");
+        if cursor.is_empty() {
             if !matches!(self.status, ReadStatus::Ok) {
                 match std::mem::replace(&mut self.status, ReadStatus::Ok) {
                     ReadStatus::Eof => return Ok(0),
@@ -72,19 +207,34 @@ impl io::Read for SafeRead {
             return Err(io::ErrorKind::WouldBlock.into());
         }
 
-        // now buffer is not empty. copy it.
-        let to_copy = buffer.len().min(buf.len());
-        unsafe { std::ptr::copy_nonoverlapping(buffer.as_ptr(), buf.as_mut_ptr(), to_copy) };
-        buffer.advance(to_copy);
+        // now the cursor is not empty. copy it.
+        let to_copy = cursor.remaining().min(buf.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (cursor.data.as_ptr() as *const u8).add(cursor.pos),
+                buf.as_mut_ptr(),
+                to_copy,
+            )
+        };
+        cursor.pos += to_copy;
+        cursor.reclaim();
 
         Ok(to_copy)
     }
 }
 
 pub(crate) struct SafeWrite {
-    // the option is only meant for temporary take, it always should be some
-    buffer: Option<BytesMut>,
+    // the option is only meant for temporary take, it always should be some.
+    // each chunk handed to `write()` is copied into its own segment instead of being
+    // appended to one contiguous buffer, so `do_io` can flush them all in a single vectored write
+    // without a defensive copy: each segment is already owned and can move into `writev`
+    // by value, which a borrow into a shared ring buffer couldn't do safely (monoio's
+    // completion-based IO can leave the kernel write running against a buffer after the
+    // future that started it is dropped, e.g. under a timeout).
+    segments: Option<VecDeque<BytesMut>>,
     status: WriteStatus,
+    capacity: usize,
+    queued_len: usize,
 }
 
 enum WriteStatus {
@@ -94,79 +244,225 @@ enum WriteStatus {
 
 impl Default for SafeWrite {
     fn default() -> Self {
+        Self::with_capacity(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+impl SafeWrite {
+    // creates a SafeWrite that queues at most `capacity` bytes of unflushed segments.
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
         Self {
-            buffer: Some(BytesMut::default()),
+            segments: Some(VecDeque::new()),
             status: WriteStatus::Ok,
+            capacity,
+            queued_len: 0,
         }
     }
-}
 
-impl SafeWrite {
     pub(crate) async fn do_io<IO: AsyncWriteRent>(&mut self, mut io: IO) -> io::Result<usize> {
-        // if the buffer is empty, just return.
-        let buffer = self.buffer.as_ref().expect("buffer ref expected");
-        if buffer.is_empty() {
+        // if there are no queued segments, just return.
+        let segments = self.segments.as_ref().expect("segments ref expected");
+        if segments.is_empty() {
             return Ok(0);
         }
 
-        // buffer is not empty now. write it.
-        let buffer = self.buffer.take().expect("buffer ownership expected");
-        let (result, buffer) = io.write_all(buffer).await;
-        self.buffer = Some(buffer);
+        // segments are not empty now. flush them with a single vectored write instead of
+        // memmoving everything into one contiguous buffer first.
+        let segments: Vec<BytesMut> = self
+            .segments
+            .take()
+            .expect("segments ownership expected")
+            .into();
+        let (result, segments) = io.writev(segments).await;
+        let mut segments: VecDeque<BytesMut> = segments.into();
         match result {
             Ok(written_len) => {
-                unsafe { self.buffer.as_mut().unwrap_unchecked().advance(written_len) };
+                self.advance_segments(&mut segments, written_len);
+                self.segments = Some(segments);
                 Ok(written_len)
             }
             Err(e) => {
+                self.segments = Some(segments);
                 let rerr = e.kind().into();
                 self.status = WriteStatus::Err(e);
                 Err(rerr)
             }
         }
     }
+
+    // drops fully-written segments off the front of the queue and trims a partially
+    // written one in place, so a short write never requires moving the remaining bytes.
+    fn advance_segments(&mut self, segments: &mut VecDeque<BytesMut>, mut written_len: usize) {
+        self.queued_len -= written_len;
+        while written_len > 0 {
+            let Some(front) = segments.front_mut() else {
+                break;
+            };
+            if written_len >= front.len() {
+                written_len -= front.len();
+                segments.pop_front();
+            } else {
+                front.advance(written_len);
+                written_len = 0;
+            }
+        }
+    }
 }
 
 impl io::Write for SafeWrite {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // if there is too much data inside the buffer, return WoundBlock
-        let buffer = self.buffer.as_mut().expect("buffer mut expected");
+        // if there is too much data already queued, return WoundBlock
         if !matches!(self.status, WriteStatus::Ok) {
             match std::mem::replace(&mut self.status, WriteStatus::Ok) {
                 WriteStatus::Err(e) => return Err(e),
                 WriteStatus::Ok => unsafe { unreachable_unchecked() },
             }
         }
-        if buffer.len() >= BUFFER_SIZE {
+        if self.queued_len >= self.capacity {
             return Err(io::ErrorKind::WouldBlock.into());
         }
 
-        // there is space inside the buffer, copy to it.
-        let space_left = BUFFER_SIZE - buffer.len();
-        buffer.reserve(space_left);
+        // there is room left in the queue; queue `buf` as its own segment.
+        let space_left = self.capacity - self.queued_len;
         let to_copy = buf.len().min(space_left);
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                buf.as_ptr(),
-                buffer.as_mut_ptr().add(buffer.len()),
-                to_copy,
-            )
-        };
-        unsafe { buffer.advance_mut(to_copy) };
+        let segments = self.segments.as_mut().expect("segments mut expected");
+        segments.push_back(BytesMut::from(&buf[..to_copy]));
+        self.queued_len += to_copy;
         Ok(to_copy)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let buffer = self.buffer.as_mut().expect("buffer mut expected");
+        let segments = self.segments.as_ref().expect("segments ref expected");
         if !matches!(self.status, WriteStatus::Ok) {
             match std::mem::replace(&mut self.status, WriteStatus::Ok) {
                 WriteStatus::Err(e) => return Err(e),
                 WriteStatus::Ok => unsafe { unreachable_unchecked() },
             }
         }
-        if !buffer.is_empty() {
+        if !segments.is_empty() {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_segments_drops_full_and_trims_partial_without_memmove() {
+        let mut w = SafeWrite::with_capacity(16);
+        assert_eq!(io::Write::write(&mut w, &[1, 2, 3]).unwrap(), 3);
+        assert_eq!(io::Write::write(&mut w, &[4, 5, 6, 7]).unwrap(), 4);
+        assert_eq!(w.queued_len, 7);
+
+        // a write shorter than the first segment only trims it in place.
+        let mut segments: VecDeque<BytesMut> = w.segments.take().unwrap();
+        w.advance_segments(&mut segments, 2);
+        assert_eq!(segments.front().unwrap().as_ref(), &[3]);
+        assert_eq!(segments.len(), 2);
+
+        // a write that finishes the first segment and starts the second drops the first
+        // outright and trims the second, never touching the bytes still queued behind it.
+        w.advance_segments(&mut segments, 2);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments.front().unwrap().as_ref(), &[5, 6, 7]);
+        w.segments = Some(segments);
+        assert_eq!(w.queued_len, 3);
+    }
+
+    #[test]
+    fn write_returns_would_block_once_capacity_is_queued() {
+        let mut w = SafeWrite::with_capacity(4);
+        assert_eq!(io::Write::write(&mut w, &[1, 2, 3, 4]).unwrap(), 4);
+        let err = io::Write::write(&mut w, &[5]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(
+            io::Write::flush(&mut w).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn ensure_capacity_resizes_backing_allocation_both_ways() {
+        let cursor = ReadCursor::with_capacity(1024);
+        assert_eq!(cursor.data.len(), 1024);
+        let grown = cursor.ensure_capacity(4096);
+        assert_eq!(grown.data.len(), 4096);
+        let shrunk = grown.ensure_capacity(1024);
+        assert_eq!(shrunk.data.len(), 1024);
+    }
+
+    #[test]
+    fn adapt_reserve_size_grows_then_shrinks_to_the_floor() {
+        let mut r = SafeRead::with_capacity(1024);
+        assert_eq!(r.reserve_size, 1024);
+
+        // a read that fills the buffer completely grows the reservation, up to the cap.
+        r.adapt_reserve_size(1024, 1024);
+        assert_eq!(r.reserve_size, 2048);
+
+        // a read that comes back mostly empty only shrinks it back down once it's happened
+        // `SHRINK_STREAK_THRESHOLD` times in a row, but never below the floor set at
+        // construction time.
+        for _ in 0..SHRINK_STREAK_THRESHOLD - 1 {
+            r.adapt_reserve_size(100, 2048);
+            assert_eq!(r.reserve_size, 2048);
+        }
+        r.adapt_reserve_size(100, 2048);
+        assert_eq!(r.reserve_size, 1024);
+        r.adapt_reserve_size(0, 1024);
+        assert_eq!(r.reserve_size, 1024);
+    }
+
+    #[test]
+    fn adapt_reserve_size_caps_growth() {
+        let mut r = SafeRead::with_capacity(MAX_RESERVE_SIZE);
+        r.adapt_reserve_size(MAX_RESERVE_SIZE, MAX_RESERVE_SIZE);
+        assert_eq!(r.reserve_size, MAX_RESERVE_SIZE);
+    }
+
+    #[test]
+    fn adapt_reserve_size_does_not_thrash_under_bursty_traffic() {
+        // a read that briefly dips below a quarter full, surrounded by reads that don't,
+        // is exactly the bursty pattern the adaptive reserve is meant to serve: it must not
+        // shrink (and so must not throw away the backing allocation) on that single dip.
+        let mut r = SafeRead::with_capacity(1024);
+        r.adapt_reserve_size(1024, 1024);
+        assert_eq!(r.reserve_size, 2048);
+
+        r.adapt_reserve_size(100, 2048);
+        assert_eq!(r.reserve_size, 2048);
+        assert_eq!(r.underfill_streak, 1);
+
+        // traffic picks back up before the shrink streak completes; the streak resets, the
+        // reservation is never shrunk, and this full read grows it as usual.
+        r.adapt_reserve_size(2048, 2048);
+        assert_eq!(r.reserve_size, 4096);
+        assert_eq!(r.underfill_streak, 0);
+    }
+
+    #[test]
+    fn read_returns_eof_once_then_would_block() {
+        let mut r = SafeRead::with_capacity(16);
+        r.status = ReadStatus::Eof;
+        let mut buf = [0u8; 4];
+        assert_eq!(io::Read::read(&mut r, &mut buf).unwrap(), 0);
+        // the Eof status is consumed by the first read; without new data the next read
+        // just blocks, rather than reporting EOF forever.
+        let err = io::Read::read(&mut r, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn read_surfaces_queued_error_once() {
+        let mut r = SafeRead::with_capacity(16);
+        r.status = ReadStatus::Err(io::Error::new(io::ErrorKind::ConnectionReset, "boom"));
+        let mut buf = [0u8; 4];
+        let err = io::Read::read(&mut r, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = io::Read::read(&mut r, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+}